@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+extern crate paho_mqtt as mqtt;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Default number of unpublished messages kept around while the
+/// broker is unreachable, before the oldest are dropped.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// Retained availability topic, set as the MQTT Last-Will and
+/// republished as a birth message on every connect.
+pub const AVAILABILITY_TOPIC: &str = "hw5800/bridge/status";
+const ONLINE_PAYLOAD: &str = "online";
+const OFFLINE_PAYLOAD: &str = "offline";
+
+fn retained_message(topic: &str, payload: &str, qos: i32) -> mqtt::Message {
+    mqtt::MessageBuilder::new()
+        .topic(topic)
+        .payload(payload)
+        .qos(qos)
+        .retained(true)
+        .finalize()
+}
+
+/// Connection details needed to (re)connect to the MQTT broker, kept
+/// as plain data so a fresh set of options can be built per attempt.
+pub struct MqttConfig {
+    pub server: String,
+    pub port: String,
+    pub client_id: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub key_store: Option<String>,
+    pub trust_store: Option<String>,
+}
+
+impl MqttConfig {
+    fn create_options(&self) -> mqtt::CreateOptions {
+        let mut create_opts = mqtt::CreateOptionsBuilder::new();
+        create_opts = create_opts
+            .server_uri(format!("tcp://{}:{}", self.server, self.port));
+        if let Some(client_id) = &self.client_id {
+            create_opts = create_opts.client_id(client_id);
+        }
+        create_opts.finalize()
+    }
+
+    fn connect_options(&self) -> mqtt::ConnectOptions {
+        let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
+
+        if let Some(user) = &self.user {
+            conn_opts.user_name(user);
+            if let Some(password) = &self.password {
+                conn_opts.password(password);
+            }
+        }
+
+        let mut ssl_opts = mqtt::SslOptionsBuilder::new();
+        let mut ssl_opts_set = false;
+        if let Some(keystore) = &self.key_store {
+            ssl_opts
+                .key_store(keystore)
+                .expect("Error loading SSL key store");
+            ssl_opts_set = true;
+        }
+        if let Some(truststore) = &self.trust_store {
+            ssl_opts
+                .trust_store(truststore)
+                .expect("Error loading SSL trust store");
+            ssl_opts_set = true;
+        }
+        if ssl_opts_set {
+            conn_opts.ssl_options(ssl_opts.finalize());
+        }
+
+        conn_opts.will_message(retained_message(
+            AVAILABILITY_TOPIC,
+            OFFLINE_PAYLOAD,
+            1,
+        ));
+
+        conn_opts.finalize()
+    }
+}
+
+// A bounded FIFO of pending (topic, payload) publishes, shared
+// between the radio thread (producer) and the MQTT thread
+// (consumer). Drops the oldest entry when full so the radio thread
+// never blocks on a slow or disconnected broker.
+struct OutboundQueue {
+    items: Mutex<VecDeque<(String, String)>>,
+    capacity: usize,
+    cv: Condvar,
+    drained: Condvar,
+}
+
+impl OutboundQueue {
+    fn new(capacity: usize) -> Self {
+        OutboundQueue {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            cv: Condvar::new(),
+            drained: Condvar::new(),
+        }
+    }
+
+    fn push(&self, topic: String, payload: String) {
+        let mut items = self.items.lock().unwrap();
+        if items.len() >= self.capacity {
+            items.pop_front();
+        }
+        items.push_back((topic, payload));
+        self.cv.notify_one();
+    }
+
+    // Put a message back at the front of the queue after a failed
+    // publish, so it's the first thing flushed on reconnect. Unlike
+    // `push`, this never drops an entry.
+    fn requeue(&self, topic: String, payload: String) {
+        let mut items = self.items.lock().unwrap();
+        items.push_front((topic, payload));
+        self.cv.notify_one();
+    }
+
+    fn pop(&self) -> (String, String) {
+        let mut items = self.items.lock().unwrap();
+        while items.is_empty() {
+            items = self.cv.wait(items).unwrap();
+        }
+        items.pop_front().unwrap()
+    }
+
+    // Wake anyone in `wait_drained` if the queue just became empty,
+    // i.e. every queued message has been handed off to a publish
+    // attempt. Called only after a successful publish; a message
+    // requeued for retry keeps the queue non-empty.
+    fn notify_if_drained(&self) {
+        let items = self.items.lock().unwrap();
+        if items.is_empty() {
+            self.drained.notify_all();
+        }
+    }
+
+    // Block until the queue is empty or `timeout` elapses, returning
+    // whether it drained in time.
+    fn wait_drained(&self, timeout: Duration) -> bool {
+        let items = self.items.lock().unwrap();
+        if items.is_empty() {
+            return true;
+        }
+        let (items, _) = self.drained.wait_timeout(items, timeout).unwrap();
+        items.is_empty()
+    }
+}
+
+/// A reconnecting MQTT publisher running on a background thread.
+/// `publish` is non-blocking; connection and reconnect-with-backoff
+/// happen off the radio thread.
+#[derive(Clone)]
+pub struct Bridge {
+    queue: Arc<OutboundQueue>,
+}
+
+impl Bridge {
+    /// Spawn the publisher thread. `discovery` is (re-)published on
+    /// every successful connect.
+    pub fn spawn(
+        config: MqttConfig,
+        capacity: usize,
+        discovery: Vec<(String, String)>,
+    ) -> Self {
+        let queue = Arc::new(OutboundQueue::new(capacity));
+        let worker_queue = queue.clone();
+        thread::spawn(move || publish_loop(config, discovery, worker_queue));
+        Bridge { queue }
+    }
+
+    /// Queue a status update for publishing. Never blocks.
+    pub fn publish(&self, topic: String, payload: String) {
+        self.queue.push(topic, payload);
+    }
+
+    /// Block until every message queued so far has been handed off
+    /// to a publish attempt, or `timeout` elapses. Used to avoid
+    /// dropping the tail of a short-lived producer (e.g. `--replay`)
+    /// that would otherwise exit before the background thread has
+    /// even finished connecting.
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        self.queue.wait_drained(timeout)
+    }
+}
+
+fn publish_loop(
+    config: MqttConfig,
+    discovery: Vec<(String, String)>,
+    queue: Arc<OutboundQueue>,
+) {
+    let cli = match mqtt::Client::new(config.create_options()) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Could not create MQTT instance: {:?}", e);
+            return;
+        }
+    };
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if let Err(e) = cli.connect(config.connect_options()) {
+            println!(
+                "Unable to connect to MQTT: {:?}; retrying in {:?}",
+                e, backoff
+            );
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            continue;
+        }
+        println!("Connected to MQTT server");
+        backoff = MIN_BACKOFF;
+
+        // Let Home Assistant (re-)discover every known device, then
+        // announce the bridge itself as available, now that we hold
+        // a fresh connection.
+        for (topic, payload) in &discovery {
+            let msg = retained_message(topic, payload, 1);
+            if let Err(e) = cli.publish(msg) {
+                println!(
+                    "Error publishing discovery config for {}: {:?}",
+                    topic, e
+                );
+            }
+        }
+        let birth = retained_message(AVAILABILITY_TOPIC, ONLINE_PAYLOAD, 1);
+        if let Err(e) = cli.publish(birth) {
+            println!("Error publishing birth message: {:?}", e);
+        }
+
+        // Drain the queue until a publish fails, then fall back out
+        // to the reconnect loop above.
+        loop {
+            let (topic, payload) = queue.pop();
+            let msg = mqtt::Message::new(topic.clone(), payload.clone(), 1);
+            if let Err(e) = cli.publish(msg) {
+                println!("Error publishing: {:?}; will reconnect", e);
+                queue.requeue(topic, payload);
+                break;
+            }
+            queue.notify_if_drained();
+        }
+    }
+}
@@ -1,12 +1,43 @@
 use std::collections::HashMap;
 use std::io;
+use std::io::Read;
+
+use serde::Deserialize;
 
 use crate::hw5800;
 
-#[derive(Debug, Clone)]
+// Status bits shared by every 5800-family frame, regardless of
+// device type.
+const BIT_TOGGLE: u8 = 0b0100_0000;
+const BIT_TAMPER: u8 = 0b0000_1000;
+const BIT_BATTERY_LOW: u8 = 0b0000_0100;
+const BIT_SUPERVISION: u8 = 0b0000_0010;
+
+// Per-type "loop" bit carrying the primary on/off state. Types never
+// share a device id, so it's fine that some of these overlap.
+const BIT_DOOR_OPEN: u8 = 0b0010_0000;
+const BIT_MOTION: u8 = 0b1000_0000;
+const BIT_SMOKE: u8 = 0b1000_0000;
+const BIT_GLASSBREAK: u8 = 0b1000_0000;
+const BIT_CO: u8 = 0b1000_0000;
+const BIT_WATER: u8 = 0b0010_0000;
+// keyfobs encode which button was pressed in the high nibble
+// rather than a single loop bit. This overlaps BIT_TOGGLE (bit 6),
+// which is fine since as_json omits the shared "tog"/"supervision"
+// fields entirely for Keyfob.
+const KEYFOB_BUTTON_MASK: u8 = 0b1111_0000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DeviceType {
     Door,
     Motion,
+    Smoke,
+    Glassbreak,
+    Co,
+    #[serde(alias = "flood")]
+    Water,
+    Keyfob,
     Unknown,
 }
 
@@ -14,21 +45,55 @@ fn io_errstr(s: &str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, s)
 }
 
-impl std::str::FromStr for DeviceType {
-    type Err = io::Error;
+impl DeviceType {
+    /// The Home Assistant `binary_sensor` device_class for this
+    /// device type, if it has MQTT discovery support.
+    fn ha_device_class(&self) -> Option<&'static str> {
+        match self {
+            DeviceType::Door => Some("door"),
+            DeviceType::Motion => Some("motion"),
+            DeviceType::Smoke => Some("smoke"),
+            DeviceType::Glassbreak => Some("safety"),
+            DeviceType::Co => Some("gas"),
+            DeviceType::Water => Some("moisture"),
+            DeviceType::Keyfob | DeviceType::Unknown => None,
+        }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.to_lowercase() == "door" {
-            Ok(DeviceType::Door)
-        } else if s.to_lowercase() == "motion" {
-            Ok(DeviceType::Motion)
-        } else {
-            Err(io_errstr("Unknown DeviceType"))
+    /// The field in `DeviceStore::as_json`'s output that carries
+    /// this device type's primary on/off state, used to build the
+    /// discovery config's value_template.
+    fn ha_state_field(&self) -> Option<&'static str> {
+        match self {
+            DeviceType::Door => Some("open"),
+            DeviceType::Motion => Some("motion"),
+            DeviceType::Smoke => Some("smoke"),
+            DeviceType::Glassbreak => Some("glassbreak"),
+            DeviceType::Co => Some("co"),
+            DeviceType::Water => Some("wet"),
+            DeviceType::Keyfob | DeviceType::Unknown => None,
         }
     }
 }
 
-pub struct DeviceStore(HashMap<u32, DeviceType>);
+/// One device entry as read from the YAML device file.
+#[derive(Debug, Clone, Deserialize)]
+struct DeviceEntry {
+    name: Option<String>,
+    #[serde(rename = "type")]
+    device_type: DeviceType,
+    topic: Option<String>,
+}
+
+/// A known device: its type, an optional friendly name, and an
+/// optional MQTT topic that overrides the default `hw5800/<ID>`.
+struct Device {
+    name: Option<String>,
+    device_type: DeviceType,
+    topic: Option<String>,
+}
+
+pub struct DeviceStore(HashMap<u32, Device>);
 
 fn yes_no(b: u8) -> &'static str {
     if b == 0 {
@@ -38,47 +103,200 @@ fn yes_no(b: u8) -> &'static str {
     }
 }
 
+fn bool_yes_no(b: bool) -> &'static str {
+    if b {
+        "y"
+    } else {
+        "n"
+    }
+}
+
 impl DeviceStore {
     pub fn new() -> Self {
         DeviceStore(HashMap::new())
     }
 
-    pub fn load<R: io::BufRead>(r: R) -> io::Result<Self> {
-        let mut map: HashMap<u32, DeviceType> = HashMap::new();
-        for lw in r.lines() {
-            let l = lw?;
-            let mut elmts = l.split_whitespace();
-            let id: u32 = u32::from_str_radix(
-                elmts.next().expect("Bad DeviceStore data"),
-                16,
-            )
-            .expect("Bad DeviceStrore id");
-            let ty: DeviceType =
-                elmts.next().expect("Bad DeviceStore data").parse()?;
-            println!("Found device: {:X} {:?}", id, ty);
-            map.insert(id, ty);
+    /// Load devices from a YAML file mapping hex device id to an
+    /// entry with a device `type`, and optionally a friendly `name`
+    /// and a `topic` override. Device ids must be quoted, since an
+    /// unquoted all-digit id (very plausible for a 3-byte hex id)
+    /// would otherwise be resolved as a YAML integer, possibly
+    /// under a non-hex radix. For example:
+    ///
+    /// ```yaml
+    /// "0A1B2C":
+    ///   name: Front Door
+    ///   type: door
+    /// "1234AB":
+    ///   type: motion
+    ///   topic: home/hallway/motion
+    /// ```
+    pub fn load<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut contents = String::new();
+        r.read_to_string(&mut contents)?;
+
+        let raw: HashMap<serde_yaml::Value, DeviceEntry> =
+            serde_yaml::from_str(&contents)
+                .map_err(|e| io_errstr(&format!("YAML parse error: {}", e)))?;
+
+        let mut map = HashMap::new();
+        for (key, entry) in raw {
+            let id_str = match &key {
+                serde_yaml::Value::String(s) => s,
+                _ => {
+                    return Err(io_errstr(
+                        "Device id must be a quoted hex string, e.g. \"0A1B2C\"",
+                    ))
+                }
+            };
+            let id = u32::from_str_radix(id_str, 16)
+                .map_err(|_| io_errstr(&format!("Bad device id: {}", id_str)))?;
+            println!(
+                "Found device: {:06X} {:?} ({})",
+                id,
+                entry.device_type,
+                entry.name.as_deref().unwrap_or("unnamed")
+            );
+            map.insert(
+                id,
+                Device {
+                    name: entry.name,
+                    device_type: entry.device_type,
+                    topic: entry.topic,
+                },
+            );
         }
         Ok(DeviceStore(map))
     }
 
+    /// The MQTT topic a device's status should be published to: its
+    /// configured override, or `hw5800/<ID>` by default.
+    pub fn topic_for(&self, id: u32) -> String {
+        self.0
+            .get(&id)
+            .and_then(|d| d.topic.clone())
+            .unwrap_or_else(|| format!("hw5800/{:X}", id))
+    }
+
     pub fn as_json(&self, status: &hw5800::HW5800Status) -> String {
-        match self.0.get(&status.id()).unwrap_or(&DeviceType::Unknown) {
-            DeviceType::Door => format!(
-                r#"{{"open":"{}","tog":"{}","b":"{:02X}"}}"#,
-                yes_no(status.bits() & 0b00100000),
-                yes_no(status.bits() & 0b01000000),
-                // maybe 0x00000100 is the poll bit (i.e. it means "no change in state")
-                status.bits()
+        let ty = self
+            .0
+            .get(&status.id())
+            .map(|d| &d.device_type)
+            .unwrap_or(&DeviceType::Unknown);
+        let bits = status.bits();
+
+        // the field(s) specific to this device's primary loop bit(s).
+        let primary = match ty {
+            DeviceType::Door => {
+                format!(r#""open":"{}","#, yes_no(bits & BIT_DOOR_OPEN))
+            }
+            DeviceType::Motion => {
+                format!(r#""motion":"{}","#, yes_no(bits & BIT_MOTION))
+            }
+            DeviceType::Smoke => {
+                format!(r#""smoke":"{}","#, yes_no(bits & BIT_SMOKE))
+            }
+            DeviceType::Glassbreak => {
+                format!(r#""glassbreak":"{}","#, yes_no(bits & BIT_GLASSBREAK))
+            }
+            DeviceType::Co => {
+                format!(r#""co":"{}","#, yes_no(bits & BIT_CO))
+            }
+            DeviceType::Water => {
+                format!(r#""wet":"{}","#, yes_no(bits & BIT_WATER))
+            }
+            DeviceType::Keyfob => format!(
+                r#""button":"{:X}","#,
+                (bits & KEYFOB_BUTTON_MASK) >> 4
             ),
-            DeviceType::Motion => format!(
-                r#"{{"motion":"{}","tog":"{}","b":"{:02X}"}}"#,
-                yes_no(status.bits() & 0b10000000),
-                yes_no(status.bits() & 0b01000000),
-                status.bits()
+            DeviceType::Unknown => String::new(),
+        };
+
+        // Keyfobs have no loop/supervision circuit: the high nibble
+        // of their status byte is the button code, which overlaps
+        // BIT_TOGGLE, so "tog" and "supervision" are omitted for that
+        // type. "tamper" and "battery_low" are still emitted below.
+        let shared = match ty {
+            DeviceType::Keyfob => String::new(),
+            _ => format!(
+                r#""tog":"{}","supervision":"{}","#,
+                yes_no(bits & BIT_TOGGLE),
+                yes_no(bits & BIT_SUPERVISION)
             ),
-            DeviceType::Unknown => {
-                format!(r#"{{"b":"{:02X}"}}"#, status.bits())
-            }
-        }
+        };
+
+        // the shared status bits every 5800-family frame carries,
+        // regardless of device type.
+        format!(
+            r#"{{{}{}"tamper":"{}","battery_low":"{}","replay":"{}","b":"{:02X}"}}"#,
+            primary,
+            shared,
+            yes_no(bits & BIT_TAMPER),
+            yes_no(bits & BIT_BATTERY_LOW),
+            bool_yes_no(status.replay_suspected()),
+            bits
+        )
+    }
+
+    /// Build Home Assistant MQTT discovery (topic, retained payload)
+    /// pairs for every known device that has discovery support, so
+    /// they appear automatically as entities instead of requiring
+    /// hand-written configuration. State/value templates match the
+    /// JSON that `as_json` emits for the device.
+    pub fn discovery_configs(&self) -> Vec<(String, String)> {
+        self.0
+            .iter()
+            .filter_map(|(id, device)| {
+                let class = device.device_type.ha_device_class()?;
+                let field = device.device_type.ha_state_field()?;
+                let name = device
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("HW5800 {:06X}", id));
+                let state_topic = self.topic_for(*id);
+                let topic =
+                    format!("homeassistant/binary_sensor/{:06X}/config", id);
+                let value_template =
+                    format!("{{{{ value_json.{} == 'y' }}}}", field);
+                let payload = format!(
+                    r#"{{"name":"{name}","unique_id":"hw5800_{id:06X}","device_class":"{class}","state_topic":"{state_topic}","value_template":"{tmpl}","payload_on":"True","payload_off":"False","availability_topic":"{avail}"}}"#,
+                    name = name,
+                    id = id,
+                    class = class,
+                    state_topic = state_topic,
+                    tmpl = value_template,
+                    avail = crate::bridge::AVAILABILITY_TOPIC,
+                );
+                Some((topic, payload))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn load_rejects_a_bare_numeric_device_id() {
+        let yaml = "012345:\n  type: door\n";
+        let err = DeviceStore::load(yaml.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("quoted"));
+    }
+
+    #[test]
+    fn load_accepts_a_quoted_device_id() {
+        let yaml = "\"012345\":\n  type: door\n";
+        let devs = DeviceStore::load(yaml.as_bytes()).unwrap();
+        assert_eq!(devs.topic_for(0x012345), "hw5800/12345");
+    }
+
+    #[test]
+    fn keyfob_button_mask_covers_every_nibble_value() {
+        let values: HashSet<u8> =
+            (0u8..=255).map(|b| (b & KEYFOB_BUTTON_MASK) >> 4).collect();
+        assert_eq!(values, (0u8..=0xF).collect());
     }
 }
@@ -1,10 +1,90 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 
 use log::info;
 
 use crc16::CrcType;
 //use textplots::{Chart, Plot, Shape};
 
+use crate::trace::{TraceEvent, Tracer};
+
+/// Default burst de-duplication window, in averaged-sample ticks.
+/// A single frame already takes on the order of ~1000 ticks to
+/// decode end to end with the default decoder params, so the window
+/// needs enough headroom to span a whole burst of repeats, not just
+/// one frame. See `HW5800::with_dedup` for details.
+pub const DEFAULT_DEDUP_WINDOW: u64 = 4096;
+
+fn io_errstr(s: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, s)
+}
+
+/// The decoder-tuning parameters in `HW5800`. These are specific to
+/// a given SDR/antenna setup and were originally hand-tuned by
+/// trial and error; `--calibrate` sweeps this space automatically
+/// for a new setup and can write out the result in this format.
+#[derive(Debug, Copy, Clone)]
+pub struct DecoderParams {
+    pub max_count: usize, // number of samples averaged in the first pass
+    pub peak_dur: usize,  // the number of samples to count a peak
+    pub threshold: f32,   // threshold for avg power for examining a buffer
+}
+
+impl Default for DecoderParams {
+    // If you're going to fiddle, you likely want max_count and
+    // peak_dur to maintain approximately a 2:1 ratio.
+    fn default() -> Self {
+        DecoderParams {
+            max_count: 19,
+            peak_dur: 10,
+            threshold: 250.,
+        }
+    }
+}
+
+impl DecoderParams {
+    /// Load parameters written by `--calibrate`: one `key=value`
+    /// pair per line, with keys `max_count`, `peak_dur`, and
+    /// `threshold`. Any key not present keeps its default value.
+    pub fn load<R: io::BufRead>(r: R) -> io::Result<Self> {
+        let mut params = DecoderParams::default();
+        for lw in r.lines() {
+            let l = lw?;
+            let mut parts = l.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "max_count" => {
+                    params.max_count = value
+                        .parse()
+                        .map_err(|_| io_errstr("Bad max_count value"))?
+                }
+                "peak_dur" => {
+                    params.peak_dur = value
+                        .parse()
+                        .map_err(|_| io_errstr("Bad peak_dur value"))?
+                }
+                "threshold" => {
+                    params.threshold = value
+                        .parse()
+                        .map_err(|_| io_errstr("Bad threshold value"))?
+                }
+                "" => {}
+                _ => return Err(io_errstr("Unknown DecoderParams key")),
+            }
+        }
+        Ok(params)
+    }
+
+    /// Write parameters in the format `load` expects.
+    pub fn save<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "max_count={}", self.max_count)?;
+        writeln!(w, "peak_dur={}", self.peak_dur)?;
+        writeln!(w, "threshold={}", self.threshold)?;
+        Ok(())
+    }
+}
+
 fn crc(b: &[u8]) -> u16 {
     let v = crc16::BUYPASS::init();
     let u = crc16::BUYPASS::update(v, &b);
@@ -20,6 +100,7 @@ fn ary_to_hex(msg: &[u8]) -> String {
 pub struct HW5800Status {
     id: u32, // actually 3 bytes
     bits: u8,
+    replay_suspected: bool,
 }
 
 impl HW5800Status {
@@ -36,6 +117,7 @@ impl HW5800Status {
         HW5800Status {
             id: ((m[0] as u32) << 16) + ((m[1] as u32) << 8) + m[2] as u32,
             bits: m[3],
+            replay_suspected: false,
         }
     }
 
@@ -50,6 +132,18 @@ impl HW5800Status {
     pub fn bits(&self) -> u8 {
         self.bits
     }
+
+    /// True if this frame's device id/bits pair was seen before,
+    /// but only after the burst de-dup window had already elapsed.
+    /// Honeywell 5800 frames carry a toggle bit that flips on every
+    /// genuine state change, so identical bits reappearing well
+    /// after the last delivery suggests a captured-and-replayed
+    /// transmission rather than a new physical event. Downstream
+    /// consumers should treat such frames with suspicion rather
+    /// than acting on them directly.
+    pub fn replay_suspected(&self) -> bool {
+        self.replay_suspected
+    }
 }
 
 // See the README for a description of the processing algorithm.
@@ -76,32 +170,95 @@ pub struct HW5800<F: Fn(&HW5800Status) -> ()> {
     on_cut: bool,     // tell if the last peak left us on cut or off cut
     msg: VecDeque<bool>, // bits of a potential message
     callback: F,      // the callback to be called with a status message
+    tick: u64,        // running count of averaged samples processed
+    dedup_enabled: bool, // whether burst repeats are suppressed
+    dedup_window: u64, // ticks within which a repeat is a burst, not a new event
+    seen: HashMap<u32, (u8, u64)>, // per-device (last bits, last delivery tick)
+    tracer: Option<Tracer>, // opt-in structured decode trace
 }
 
 impl<F: Fn(&HW5800Status) -> ()> HW5800<F> {
-    /// Create a HW5800 with default parameters.
+    /// Create a HW5800 with default decode parameters.
     /// Callback will be called with a HW5800Status object
     /// containing the contents of the HW5800 message.
     pub fn new(callback: F) -> Self {
-        // these parameters were determined by trial and
-        // error on my device. YMMV.
-        // If you're going to fiddle, you likely want max_count and peak_dur to
-        // maintain approximately a 2:1 ratio.
+        Self::with_decoder_params(callback, DecoderParams::default())
+    }
+
+    /// Create a HW5800 using explicit decoder-tuning parameters,
+    /// e.g. ones found via `--calibrate`, instead of the defaults.
+    pub fn with_decoder_params(callback: F, params: DecoderParams) -> Self {
         HW5800 {
             current: vec![(0., 0.)],
-            max_count: 19,
-            peak_dur: 10,
+            max_count: params.max_count,
+            peak_dur: params.peak_dur,
             buffer: VecDeque::new(),
             max_buffer: 128,
-            threshold: 250.,
+            threshold: params.threshold,
             lst: Peak { hi: true, dur: 0 },
             cur: Peak { hi: false, dur: 0 },
             on_cut: true,
             msg: VecDeque::new(),
             callback: callback,
+            tick: 0,
+            dedup_enabled: true,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            seen: HashMap::new(),
+            tracer: None,
+        }
+    }
+
+    /// Create a HW5800 with the default decode parameters but an
+    /// explicit burst de-duplication configuration. Real sensors
+    /// transmit several identical frames per physical event; frames
+    /// from the same device with the same bits seen within
+    /// `dedup_window` averaged-sample ticks of the last delivery are
+    /// treated as repeats of that event and, when `dedup_enabled` is
+    /// true, suppressed rather than delivered to the callback.
+    pub fn with_dedup(
+        callback: F,
+        dedup_enabled: bool,
+        dedup_window: u64,
+    ) -> Self {
+        HW5800 {
+            dedup_enabled,
+            dedup_window,
+            ..Self::new(callback)
         }
     }
 
+    /// Create a HW5800 with both explicit decoder-tuning parameters
+    /// and an explicit burst de-duplication configuration. See
+    /// `with_decoder_params` and `with_dedup`.
+    pub fn with_params(
+        callback: F,
+        params: DecoderParams,
+        dedup_enabled: bool,
+        dedup_window: u64,
+    ) -> Self {
+        HW5800 {
+            dedup_enabled,
+            dedup_window,
+            ..Self::with_decoder_params(callback, params)
+        }
+    }
+
+    /// Change the burst de-duplication configuration after
+    /// construction, e.g. to disable it while scoring candidate
+    /// decoder parameters in `--calibrate`.
+    pub fn set_dedup(&mut self, dedup_enabled: bool, dedup_window: u64) {
+        self.dedup_enabled = dedup_enabled;
+        self.dedup_window = dedup_window;
+    }
+
+    /// Attach a structured decode trace. Every subsequent buffer
+    /// power decision, peak, and candidate frame is recorded through
+    /// it, which is useful for diagnosing a capture offline or
+    /// building a regression fixture from it. Off by default.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
     /// Present the next sample from the radio to the processing.
     /// Can cause a call to the HW5800's callback if a message is
     /// detected.
@@ -119,10 +276,18 @@ impl<F: Fn(&HW5800Status) -> ()> HW5800<F> {
     }
 
     fn averaged_sample(&mut self, sample: f32) {
+        self.tick += 1;
         self.buffer.push_back(sample);
         if self.buffer.len() >= self.max_buffer {
             let avg: f32 =
                 self.buffer.iter().sum::<f32>() / self.buffer.len() as f32;
+            if let Some(t) = &mut self.tracer {
+                t.log(TraceEvent::BufferPower {
+                    avg,
+                    threshold: self.threshold,
+                    above: avg >= self.threshold,
+                });
+            }
             if avg < self.threshold {
                 self.buffer.clear();
             } else {
@@ -180,10 +345,20 @@ impl<F: Fn(&HW5800Status) -> ()> HW5800<F> {
 
                     // check the CRC
                     let c = crc(&m[..4]);
-                    if m[4] == (c >> 8) as u8 && m[5] == (c & 0xff) as u8 {
+                    let crc_ok =
+                        m[4] == (c >> 8) as u8 && m[5] == (c & 0xff) as u8;
+                    if let Some(t) = &mut self.tracer {
+                        t.log(TraceEvent::Frame {
+                            bytes: &m,
+                            crc_ok,
+                        });
+                    }
+                    if crc_ok {
                         info!("VALID: {}", ary_to_hex(&m));
-                        let status = HW5800Status::new(&m);
-                        (self.callback)(&status);
+                        let mut status = HW5800Status::new(&m);
+                        if !self.dedup_and_mark(&mut status) {
+                            (self.callback)(&status);
+                        }
                         // remove the message
                         for _ in 0..(6 * 8) {
                             self.msg.pop_front();
@@ -198,6 +373,36 @@ impl<F: Fn(&HW5800Status) -> ()> HW5800<F> {
         }
     }
 
+    // Modeled on WireGuard's anti-replay window: each device id maps
+    // to the bits and tick of the last frame delivered for it. A
+    // repeat of those same bits within `dedup_window` ticks is just
+    // the sensor's usual burst of identical transmissions for one
+    // event, so it is suppressed (when dedup is enabled). A repeat
+    // outside that window is a different matter: the toggle bit
+    // should have flipped by then for a genuine retrigger, so
+    // identical bits suggest a captured-and-replayed transmission,
+    // and the status is marked accordingly rather than suppressed.
+    fn dedup_and_mark(&mut self, status: &mut HW5800Status) -> bool {
+        let id = status.id();
+        let bits = status.bits();
+        let tick = self.tick;
+        let suppress = match self.seen.get(&id) {
+            Some(&(last_bits, last_tick)) if bits == last_bits => {
+                if tick - last_tick <= self.dedup_window {
+                    self.dedup_enabled
+                } else {
+                    status.replay_suspected = true;
+                    false
+                }
+            }
+            _ => false,
+        };
+        if !suppress {
+            self.seen.insert(id, (bits, tick));
+        }
+        suppress
+    }
+
     fn message_begin(&self) -> u8 {
         let mut acc = 0u8;
         for b in self.msg.iter().take(8) {
@@ -241,6 +446,12 @@ impl<F: Fn(&HW5800Status) -> ()> HW5800<F> {
     // Presume the newest information is correct and set it as
     // though we were on cut.
     fn transition(&mut self) {
+        if let Some(t) = &mut self.tracer {
+            t.log(TraceEvent::Peak {
+                hi: self.cur.hi,
+                dur: self.cur.dur,
+            });
+        }
         if self.on_cut {
             self.msg.push_back(self.cur.hi);
         }
@@ -251,3 +462,116 @@ impl<F: Fn(&HW5800Status) -> ()> HW5800<F> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Turn a target MSB-first bitstream into a sequence of (hi, run
+    // length) peaks that will decode back into exactly those bits
+    // once fed through the same on_cut state machine as `transition`.
+    fn encode_bits(bits: &[bool], peak_dur: usize) -> Vec<(bool, usize)> {
+        let short = peak_dur - 3;
+        let long = peak_dur + 3;
+        let mut runs = Vec::new();
+        let mut on_cut = true;
+        let mut hi = bits[0];
+        let mut i = 0;
+        while i < bits.len() {
+            if on_cut {
+                let repeat_next = i + 1 < bits.len() && bits[i + 1] == bits[i];
+                runs.push((hi, if repeat_next { short } else { long }));
+                on_cut = !repeat_next;
+                i += 1;
+            } else {
+                runs.push((hi, long));
+                on_cut = true;
+            }
+            hi = !hi;
+        }
+        runs.push((hi, long)); // one more run to flush the last real one
+        runs
+    }
+
+    fn msb_bits(bytes: &[u8]) -> Vec<bool> {
+        bytes
+            .iter()
+            .flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1 == 1))
+            .collect()
+    }
+
+    fn frame_bits(id: u32, status_byte: u8, peak_dur: usize) -> Vec<(bool, usize)> {
+        let mut m = [(id >> 16) as u8, (id >> 8) as u8, id as u8, status_byte, 0, 0];
+        let c = crc(&m[..4]);
+        m[4] = (c >> 8) as u8;
+        m[5] = (c & 0xff) as u8;
+
+        let mut frame = vec![0xFEu8];
+        frame.extend_from_slice(&m);
+        encode_bits(&msb_bits(&frame), peak_dur)
+    }
+
+    // Feed `runs` of (hi, duration-in-ticks) peaks into `hw5800` as
+    // raw IQ samples, then pad past the next full averaging-buffer
+    // window so every run, including the last, is actually
+    // processed.
+    fn feed_runs<F: Fn(&HW5800Status) -> ()>(
+        hw5800: &mut HW5800<F>,
+        runs: &[(bool, usize)],
+    ) {
+        let low = 260.0f32.sqrt();
+        let high = 600.0f32.sqrt();
+        let mut feed = |hi: bool, dur: usize| {
+            let real = if hi { high } else { low };
+            for _ in 0..dur {
+                for _ in 0..hw5800.max_count {
+                    hw5800.add_sample(real, 0.);
+                }
+            }
+        };
+        for &(hi, dur) in runs {
+            feed(hi, dur);
+        }
+
+        let ticks: usize = runs.iter().map(|(_, dur)| dur).sum();
+        let padding = 128 - (ticks % 128) + 128;
+        feed(false, padding);
+    }
+
+    #[test]
+    fn replay_decodes_a_synthesized_frame() {
+        let params = DecoderParams::default();
+        let id: u32 = 0x01A2B3;
+        let status_byte: u8 = 0b1000_0010;
+        let runs = frame_bits(id, status_byte, params.peak_dur);
+
+        let received: Cell<Option<HW5800Status>> = Cell::new(None);
+        let mut hw5800 = HW5800::new(|s: &HW5800Status| received.set(Some(*s)));
+        feed_runs(&mut hw5800, &runs);
+
+        let status = received.get().expect("frame was not decoded");
+        assert_eq!(status.id(), id);
+        assert_eq!(status.bits(), status_byte);
+    }
+
+    #[test]
+    fn dedup_suppresses_a_burst_repeat() {
+        let params = DecoderParams::default();
+        let id: u32 = 0x0ABCDE;
+        let status_byte: u8 = 0b0010_0000;
+
+        let mut runs = frame_bits(id, status_byte, params.peak_dur);
+        runs.extend(frame_bits(id, status_byte, params.peak_dur));
+
+        let count: Cell<usize> = Cell::new(0);
+        let mut hw5800 = HW5800::new(|_: &HW5800Status| count.set(count.get() + 1));
+        feed_runs(&mut hw5800, &runs);
+
+        assert_eq!(
+            count.get(),
+            1,
+            "a repeat within the dedup window should be suppressed"
+        );
+    }
+}
@@ -1,15 +1,115 @@
-use std::process;
-
-extern crate paho_mqtt as mqtt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 extern crate clap;
 
+pub mod bridge;
 pub mod devices;
 pub mod hw5800;
+pub mod trace;
+
+/// Candidate thresholds swept by `--calibrate`.
+const CALIBRATE_THRESHOLDS: [f32; 7] =
+    [100., 150., 200., 250., 300., 350., 400.];
+
+/// Candidate `max_count` values swept by `--calibrate`, as
+/// (inclusive) bounds. `peak_dur` is derived from each as roughly
+/// half, keeping the ~2:1 ratio `DecoderParams::default` uses.
+const CALIBRATE_MAX_COUNT_RANGE: (usize, usize) = (12, 28);
+
+/// How long to capture samples for during `--calibrate`.
+const CALIBRATE_DURATION: Duration = Duration::from_secs(10);
+
+/// How long to give the MQTT bridge to flush its queue before the
+/// process exits, e.g. after a `--replay` run finishes almost
+/// immediately and would otherwise race the bridge's own connect.
+const BRIDGE_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Feed a raw interleaved-u8 IQ buffer, as produced by the RTL-SDR
+/// (and as written/read by --record/--replay), through the decoder.
+fn feed_samples<F: Fn(&hw5800::HW5800Status) -> ()>(
+    hw5800: &mut hw5800::HW5800<F>,
+    bytes: &[u8],
+) {
+    (0..bytes.len()).step_by(2).for_each(|i| {
+        let real: f32 = (bytes[i] as f32) - 127.;
+        let imag: f32 = (bytes[i + 1] as f32) - 127.;
+        hw5800.add_sample(real, imag);
+    });
+}
 
 /// Open a rtl-sdr device and watch for HW5800 messages, calling
-/// the provided function when one is seen.
-fn hw5800<F: Fn(&hw5800::HW5800Status) -> ()>(f: F, device: u32) {
+/// the provided function when one is seen. If `record` is given,
+/// the raw interleaved IQ stream read from the radio is written
+/// there as it arrives, so it can be fed back in later with
+/// `replay`.
+fn hw5800<F: Fn(&hw5800::HW5800Status) -> ()>(
+    f: F,
+    device: u32,
+    params: hw5800::DecoderParams,
+    dedup_enabled: bool,
+    dedup_window: u64,
+    tracer: Option<trace::Tracer>,
+    record: Option<String>,
+) {
+    let (mut ctl, mut reader) = rtlsdr_mt::open(device)
+        .expect(&format!("Could not open RTL-SDR device {}", device));
+
+    ctl.enable_agc().expect("Could not set auto-gain");
+    ctl.set_ppm(60).expect("Could not set PPM");
+    ctl.set_center_freq(345_000_000)
+        .expect("Could not set frequency");
+    ctl.set_sample_rate(1_000_000)
+        .expect("Could not set sample rate");
+
+    let mut hw5800 =
+        hw5800::HW5800::with_params(f, params, dedup_enabled, dedup_window);
+    if let Some(t) = tracer {
+        hw5800.set_tracer(t);
+    }
+
+    let mut record_file = record.map(|path| {
+        std::fs::File::create(&path).expect("Could not create record file")
+    });
+
+    reader
+        .read_async(4, 32768, move |bytes| {
+            if let Some(file) = &mut record_file {
+                use std::io::Write;
+                if let Err(e) = file.write_all(bytes) {
+                    println!("Error writing record file: {:?}", e);
+                }
+            }
+            feed_samples(&mut hw5800, bytes);
+        })
+        .expect("Error reading from RTL-SDR");
+}
+
+/// Replay a raw interleaved IQ file, previously captured with
+/// `--record`, through the decoder with no radio attached, so
+/// decoding is fully deterministic and testable in CI.
+fn replay<F: Fn(&hw5800::HW5800Status) -> ()>(
+    f: F,
+    path: &str,
+    params: hw5800::DecoderParams,
+    dedup_enabled: bool,
+    dedup_window: u64,
+    tracer: Option<trace::Tracer>,
+) {
+    let bytes = std::fs::read(path).expect("Could not read replay file");
+    let mut hw5800 =
+        hw5800::HW5800::with_params(f, params, dedup_enabled, dedup_window);
+    if let Some(t) = tracer {
+        hw5800.set_tracer(t);
+    }
+    feed_samples(&mut hw5800, &bytes);
+}
+
+/// Capture a raw interleaved IQ stream from the RTL-SDR for
+/// `duration`, with no decoding: used by `--calibrate` to grab a few
+/// seconds of samples once, then sweep decoder parameters against
+/// them offline instead of against a live, moving-target radio feed.
+fn capture_samples(device: u32, duration: Duration) -> Vec<u8> {
     let (mut ctl, mut reader) = rtlsdr_mt::open(device)
         .expect(&format!("Could not open RTL-SDR device {}", device));
 
@@ -20,17 +120,111 @@ fn hw5800<F: Fn(&hw5800::HW5800Status) -> ()>(f: F, device: u32) {
     ctl.set_sample_rate(1_000_000)
         .expect("Could not set sample rate");
 
-    let mut hw5800 = hw5800::HW5800::new(f);
+    std::thread::spawn(move || {
+        std::thread::sleep(duration);
+        ctl.cancel_async_read();
+    });
 
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let sink = captured.clone();
     reader
         .read_async(4, 32768, move |bytes| {
-            (0..bytes.len()).step_by(2).for_each(|i| {
-                let real: f32 = (bytes[i] as f32) - 127.;
-                let imag: f32 = (bytes[i + 1] as f32) - 127.;
-                hw5800.add_sample(real, imag);
-            });
+            sink.lock().unwrap().extend_from_slice(bytes);
         })
         .expect("Error reading from RTL-SDR");
+
+    Arc::try_unwrap(captured)
+        .expect("Capture thread outlived read_async")
+        .into_inner()
+        .unwrap()
+}
+
+/// Replay `samples` through a HW5800 configured with `params` and
+/// with burst de-duplication disabled, and count how many CRC-valid
+/// frames were seen from `expected_id`. Used to score a candidate
+/// parameter set during `--calibrate`.
+fn score_params(
+    samples: &[u8],
+    params: hw5800::DecoderParams,
+    expected_id: u32,
+) -> usize {
+    let count = std::cell::Cell::new(0usize);
+    let mut hw5800 = hw5800::HW5800::with_decoder_params(
+        |status: &hw5800::HW5800Status| {
+            if status.id() == expected_id {
+                count.set(count.get() + 1);
+            }
+        },
+        params,
+    );
+    hw5800.set_dedup(false, 0);
+    feed_samples(&mut hw5800, samples);
+    count.get()
+}
+
+/// Interactive wizard that removes the trial-and-error from porting
+/// the decoder to a new SDR or antenna: capture a few seconds of
+/// samples while the user repeatedly triggers a known sensor, sweep
+/// the decoder parameter space against that single capture, and
+/// report (and optionally save) whichever combination decoded the
+/// most frames from that device.
+fn calibrate(device: u32, expected_id: u32, config_out: Option<String>) {
+    println!(
+        "Calibrating for device {:06X}. Trigger it repeatedly over the \
+        next {} seconds...",
+        expected_id,
+        CALIBRATE_DURATION.as_secs()
+    );
+    let samples = capture_samples(device, CALIBRATE_DURATION);
+    println!(
+        "Captured {} bytes. Sweeping decoder parameters...",
+        samples.len()
+    );
+
+    let (min_max_count, max_max_count) = CALIBRATE_MAX_COUNT_RANGE;
+    let mut best: Option<(usize, hw5800::DecoderParams)> = None;
+    for max_count in min_max_count..=max_max_count {
+        let peak_dur = (max_count / 2).max(1);
+        for &threshold in CALIBRATE_THRESHOLDS.iter() {
+            let params = hw5800::DecoderParams {
+                max_count,
+                peak_dur,
+                threshold,
+            };
+            let score = score_params(&samples, params, expected_id);
+            if best.map_or(true, |(best_score, _)| score > best_score) {
+                best = Some((score, params));
+            }
+        }
+    }
+
+    match best {
+        Some((score, params)) if score > 0 => {
+            println!(
+                "Best parameters: max_count={} peak_dur={} threshold={} \
+                ({} valid frames from {:06X})",
+                params.max_count,
+                params.peak_dur,
+                params.threshold,
+                score,
+                expected_id
+            );
+            if let Some(path) = config_out {
+                let mut file = std::fs::File::create(&path)
+                    .expect("Could not create config file");
+                params
+                    .save(&mut file)
+                    .expect("Could not write config file");
+                println!("Wrote parameters to {}", path);
+            }
+        }
+        _ => println!(
+            "No parameter combination decoded a frame from {:06X}. Try \
+            triggering the sensor more during capture, or moving it \
+            closer to the antenna.",
+            expected_id
+        ),
+    }
 }
 
 fn main() {
@@ -70,10 +264,11 @@ fn main() {
             .long("device-file")
             .value_name("FILE")
             .takes_value(true)
-            .help("File containing device identifications.")
-            .long_help("File containing device identifications. \
-            Each line contains a 3-byte hex device ID and a device type. \
-            Valid device types: {door, motion}"))
+            .help("YAML file containing device identifications.")
+            .long_help("YAML file mapping 3-byte hex device IDs to a \
+            device type, and optionally a friendly name and a MQTT \
+            topic override. Valid device types: {door, motion, \
+            smoke, glassbreak, co, water, keyfob}"))
         .arg(clap::Arg::with_name("rtl-number")
             .short("r")
             .long("rtl-number")
@@ -98,6 +293,60 @@ fn main() {
             .value_name("TRUST_STORE")
             .takes_value(true)
             .help("File containing the SSL trust store to use (.crt file)"))
+        .arg(clap::Arg::with_name("dedup-window")
+            .long("dedup-window")
+            .value_name("TICKS")
+            .takes_value(true)
+            .help("Burst de-duplication window, in averaged-sample ticks. \
+            Defaults to 4096.")
+            .long_help("Real 5800 sensors transmit a burst of identical \
+            frames per physical event. A repeated frame from the same \
+            device within this many averaged-sample ticks of the last \
+            delivery is treated as part of that same burst and \
+            suppressed. Defaults to 4096."))
+        .arg(clap::Arg::with_name("no-dedup")
+            .long("no-dedup")
+            .takes_value(false)
+            .help("Disable burst de-duplication; deliver every \
+            CRC-valid frame."))
+        .arg(clap::Arg::with_name("record")
+            .long("record")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write the raw IQ stream read from the RTL-SDR to \
+            FILE, for later use with --replay."))
+        .arg(clap::Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Replay a raw IQ stream previously written by \
+            --record through the decoder, with no radio attached."))
+        .arg(clap::Arg::with_name("trace")
+            .long("trace")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write a JSONL trace of each decode stage (buffer \
+            power, peaks, candidate frames, CRC results) to FILE."))
+        .arg(clap::Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Load decoder-tuning parameters (max_count, peak_dur, \
+            threshold) from FILE, e.g. one written by --calibrate."))
+        .arg(clap::Arg::with_name("calibrate")
+            .long("calibrate")
+            .value_name("DEVICE_ID")
+            .takes_value(true)
+            .help("Run the calibration wizard instead of decoding: \
+            capture a short sample while DEVICE_ID (hex) is \
+            repeatedly triggered, then sweep decoder parameters \
+            against it and report the best-scoring set."))
+        .arg(clap::Arg::with_name("calibrate-out")
+            .long("calibrate-out")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("With --calibrate, also write the best-scoring \
+            parameters to FILE in the format --config reads."))
         .get_matches();
 
     // parse the device number.
@@ -112,6 +361,51 @@ fn main() {
         0
     };
 
+    // if --calibrate was given, run the wizard and exit; it doesn't
+    // decode or publish anything itself.
+    if let Some(id_str) = args.value_of("calibrate") {
+        let expected_id = match u32::from_str_radix(id_str, 16) {
+            Ok(id) => id,
+            Err(_) => {
+                println!("Could not parse device id from: {}", id_str);
+                return;
+            }
+        };
+        calibrate(
+            rtl_num,
+            expected_id,
+            args.value_of("calibrate-out").map(String::from),
+        );
+        return;
+    }
+
+    // parse the decoder-tuning parameters
+    let decoder_params = if let Some(path) = args.value_of("config") {
+        let file = std::fs::File::open(path).expect("Error opening config file");
+        hw5800::DecoderParams::load(std::io::BufReader::new(file))
+            .expect("Error parsing config file")
+    } else {
+        hw5800::DecoderParams::default()
+    };
+
+    // parse the dedup window
+    let dedup_window = if let Some(w) = args.value_of("dedup-window") {
+        if let Ok(wu) = w.parse::<u64>() {
+            wu
+        } else {
+            println!("Could not parse dedup window from: {}", w);
+            return;
+        }
+    } else {
+        hw5800::DEFAULT_DEDUP_WINDOW
+    };
+    let dedup_enabled = !args.is_present("no-dedup");
+
+    // parse the trace file
+    let tracer = args.value_of("trace").map(|path| {
+        trace::Tracer::create(path).expect("Could not create trace file")
+    });
+
     // parse the device file
     let devs = if let Some(devfile) = args.value_of("device-file") {
         let file =
@@ -123,83 +417,88 @@ fn main() {
     };
 
     // if the server is provided, include MQTT posting
-    // code in the callback.
-    if let Some(server) = args.value_of("server") {
-        let port = args.value_of("port").unwrap_or("1883");
-        let mut create_opts = mqtt::CreateOptionsBuilder::new();
-        create_opts =
-            create_opts.server_uri(format!("tcp://{}:{}", server, port));
-
-        if let Some(client_id) = args.value_of("client-id") {
-            create_opts = create_opts.client_id(client_id);
-        }
-        // create_opts done.
-
-        let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
-
-        if let Some(user) = args.value_of("user") {
-            conn_opts.user_name(user);
-            if let Some(password) = args.value_of("password") {
-                conn_opts.password(password);
-            }
-        }
-
-        let mut ssl_opts = mqtt::SslOptionsBuilder::new();
-        //ssl_opts.ssl_version(mqtt::ssl_options::SslVersion::Tls_1_2);
-        let mut ssl_opts_set = false;
-        if let Some(keystore) = args.value_of("key-store") {
-            ssl_opts
-                .key_store(keystore)
-                .expect("Error loading SSL key store");
-            ssl_opts_set = true;
-        }
-
-        if let Some(truststore) = args.value_of("trust-store") {
-            ssl_opts
-                .trust_store(truststore)
-                .expect("Error loading SSL trust store");
-            ssl_opts_set = true;
-        }
-
-        if ssl_opts_set {
-            conn_opts.ssl_options(ssl_opts.finalize());
-        }
-
-        let cli = mqtt::Client::new(create_opts.finalize())
-            .expect("Could not create MQTT instance");
+    // code in the callback. The actual connection, reconnection, and
+    // flushing happens on a dedicated thread started by the bridge;
+    // the radio thread only ever queues messages for it.
+    let mut bridge_handle: Option<bridge::Bridge> = None;
 
-        // Connect and wait for it to complete or fail
-        if let Err(e) = cli.connect(conn_opts.finalize()) {
-            println!("Unable to connect to MQTT: {:?}", e);
-            process::exit(1);
-        }
+    let callback: Box<dyn Fn(&hw5800::HW5800Status)> =
+        if let Some(server) = args.value_of("server") {
+            let port = args.value_of("port").unwrap_or("1883").to_string();
+            let mqtt_config = bridge::MqttConfig {
+                server: server.to_string(),
+                port,
+                client_id: args.value_of("client-id").map(String::from),
+                user: args.value_of("user").map(String::from),
+                password: args.value_of("password").map(String::from),
+                key_store: args.value_of("key-store").map(String::from),
+                trust_store: args.value_of("trust-store").map(String::from),
+            };
+            let bridge = bridge::Bridge::spawn(
+                mqtt_config,
+                bridge::DEFAULT_QUEUE_CAPACITY,
+                devs.discovery_configs(),
+            );
+            bridge_handle = Some(bridge.clone());
 
-        hw5800(
-            |status: &hw5800::HW5800Status| {
+            Box::new(move |status: &hw5800::HW5800Status| {
                 let payload = devs.as_json(status);
+                if status.replay_suspected() {
+                    println!(
+                        "WARNING: Device: {:02X} repeated status {} without \
+                        a toggle-bit change; possible replay",
+                        status.id(),
+                        payload
+                    );
+                }
                 println!(
                     "PUBLISHING: Device: {:02X} status: {}",
                     status.id(),
                     payload
                 );
-                let topic = format!("hw5800/{:X}", status.id());
-                let msg = mqtt::Message::new(topic, payload, 1);
-                if let Err(e) = cli.publish(msg) {
-                    println!("Error publishing: {:?}", e);
-                    // exit so we can restart and reconnect
-                    process::exit(1);
+                bridge.publish(devs.topic_for(status.id()), payload);
+            })
+        } else {
+            // no MQTT server was provided, just print to stdout.
+            Box::new(move |status: &hw5800::HW5800Status| {
+                let payload = devs.as_json(status);
+                if status.replay_suspected() {
+                    println!(
+                        "WARNING: Device: {:02X} repeated status {} without \
+                        a toggle-bit change; possible replay",
+                        status.id(),
+                        payload
+                    );
                 }
-            },
-            rtl_num,
+                println!("Device: {:02X} status: {}", status.id(), payload);
+            })
+        };
+
+    if let Some(path) = args.value_of("replay") {
+        replay(
+            callback,
+            path,
+            decoder_params,
+            dedup_enabled,
+            dedup_window,
+            tracer,
         );
     } else {
-        // no MQTT server was provided, just print to stdout.
         hw5800(
-            |status: &hw5800::HW5800Status| {
-                let payload = devs.as_json(status);
-                println!("Device: {:02X} status: {}", status.id(), payload);
-            },
+            callback,
             rtl_num,
+            decoder_params,
+            dedup_enabled,
+            dedup_window,
+            tracer,
+            args.value_of("record").map(String::from),
         );
     }
+
+    // `replay` in particular can return almost immediately; give the
+    // bridge a chance to connect and flush before the process exits
+    // out from under it.
+    if let Some(bridge) = bridge_handle {
+        bridge.wait_drained(BRIDGE_DRAIN_TIMEOUT);
+    }
 }
@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+fn ary_to_hex(msg: &[u8]) -> String {
+    let v: Vec<String> = msg.iter().map(|b| format!("{:02X}", b)).collect();
+    v.join(" ")
+}
+
+/// One structured decode-pipeline event, written as a single JSON
+/// line so a capture's trace can be diffed or grepped.
+#[derive(Debug)]
+pub enum TraceEvent<'a> {
+    /// An averaged-sample buffer's power was compared against the
+    /// detection threshold.
+    BufferPower { avg: f32, threshold: f32, above: bool },
+    /// A high/low peak run finished and was timed.
+    Peak { hi: bool, dur: usize },
+    /// A full candidate frame was assembled and CRC-checked.
+    Frame { bytes: &'a [u8], crc_ok: bool },
+}
+
+/// Writes `TraceEvent`s to a file, one JSON object per line.
+pub struct Tracer {
+    file: File,
+}
+
+impl Tracer {
+    /// Create a tracer writing to `path`, truncating it if it
+    /// already exists.
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Tracer {
+            file: File::create(path)?,
+        })
+    }
+
+    pub fn log(&mut self, event: TraceEvent) {
+        let line = match event {
+            TraceEvent::BufferPower {
+                avg,
+                threshold,
+                above,
+            } => format!(
+                r#"{{"type":"buffer_power","avg":{},"threshold":{},"above":{}}}"#,
+                avg, threshold, above
+            ),
+            TraceEvent::Peak { hi, dur } => {
+                format!(r#"{{"type":"peak","hi":{},"dur":{}}}"#, hi, dur)
+            }
+            TraceEvent::Frame { bytes, crc_ok } => format!(
+                r#"{{"type":"frame","bytes":"{}","crc_ok":{}}}"#,
+                ary_to_hex(bytes),
+                crc_ok
+            ),
+        };
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            println!("Error writing trace event: {:?}", e);
+        }
+    }
+}